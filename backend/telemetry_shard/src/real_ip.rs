@@ -38,13 +38,18 @@ appending one to the end. So, take the first of these if it exists.
 If still no luck, look for the X-Real-IP header, which we expect to contain a single IP address.
 
 If that _still_ doesn't work, fall back to the socket address of the connection.
+
+Which sources are consulted, in which order, and which header names they're read
+from is configurable via [`RealIpConfig`].
 */
 
 /// The source of the address returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Source {
     ForwardedHeader,
     XForwardedForHeader,
     XRealIpHeader,
+    CfConnectingIpHeader,
     SocketAddr,
 }
 
@@ -54,74 +59,154 @@ impl std::fmt::Display for Source {
             Source::ForwardedHeader => write!(f, "'Forwarded' header"),
             Source::XForwardedForHeader => write!(f, "'X-Forwarded-For' header"),
             Source::XRealIpHeader => write!(f, "'X-Real-Ip' header"),
+            Source::CfConnectingIpHeader => write!(f, "'CF-Connecting-IP' header"),
             Source::SocketAddr => write!(f, "Socket address"),
         }
     }
 }
 
-pub fn real_ip(addr: SocketAddr, headers: &HeaderMap) -> (IpAddr, Source) {
-    // 打印所有的头部信息
-    for (key, value) in headers.iter() {
-        if let Ok(value_str) = value.to_str() {
-            info!("Header: {}: {}", key, value_str);
+/// Configures which sources [`real_ip`] consults, in what order, and which
+/// header names to read them from. The socket address of the connection is
+/// always the final fallback, so it isn't listed in `sources`.
+///
+/// [`RealIpConfig::default`] keeps the server's historical order of
+/// `X-Forwarded-For` (read from the `x-original-forwarded-for` header) and
+/// then `X-Real-Ip`, with the RFC 7239 `Forwarded` header tried ahead of both;
+/// it trusts the rightmost `X-Forwarded-For` entry and never consults
+/// `CF-Connecting-IP` (which is only safe to trust when the server is known
+/// to sit behind a CDN that sets it itself).
+pub struct RealIpConfig {
+    /// The header-based sources to examine, most trusted first, each paired
+    /// with the header name to read it from.
+    pub sources: Vec<(Source, String)>,
+    /// The number of reverse proxies between this server and the internet
+    /// that are trusted to append to (and not forge) the `X-Forwarded-For`
+    /// chain. See [`get_trusted_addr_from_x_forwarded_for_header`] for how
+    /// it's used; `0` trusts only the rightmost entry in the chain.
+    pub trusted_hops: usize,
+}
+
+impl Default for RealIpConfig {
+    fn default() -> Self {
+        RealIpConfig {
+            sources: vec![
+                (Source::ForwardedHeader, "forwarded".to_owned()),
+                (Source::XForwardedForHeader, "x-original-forwarded-for".to_owned()),
+                (Source::XRealIpHeader, "x-real-ip".to_owned()),
+            ],
+            trusted_hops: 0,
         }
     }
+}
 
-    let x_forwarded_for = headers.get("x-original-forwarded-for").and_then(header_as_str);
-    let real_ip = headers.get("x-real-ip").and_then(header_as_str);
+pub fn real_ip(addr: SocketAddr, headers: &HeaderMap, config: &RealIpConfig) -> (IpAddr, Source) {
+    for (source, header_name) in &config.sources {
+        let value = match headers.get(header_name.as_str()).and_then(header_as_str) {
+            Some(value) => value,
+            None => continue,
+        };
 
-    pick_best_ip_from_options(x_forwarded_for, real_ip, addr)
-}
+        let ip_addr = match source {
+            Source::ForwardedHeader => {
+                info!("Processing Forwarded header: {}", value);
+                get_first_addr_from_forwarded_header(value).and_then(parse_ip_address)
+            }
+            Source::XForwardedForHeader => {
+                info!("Processing X-Forwarded-For header: {}", value);
+                get_trusted_addr_from_x_forwarded_for_header(value, config.trusted_hops)
+                    .and_then(parse_ip_address)
+            }
+            Source::XRealIpHeader => {
+                info!("Processing X-Real-Ip header: {}", value);
+                value.trim().parse::<IpAddr>().ok()
+            }
+            Source::CfConnectingIpHeader => {
+                // Cloudflare sends a bare address with no port (and, for
+                // IPv6, no brackets), so parse it directly rather than via
+                // `parse_ip_address`, whose port-stripping would otherwise
+                // mistake the last `:` of an unbracketed IPv6 address for one.
+                info!("Processing CF-Connecting-IP header: {}", value);
+                value.trim().parse::<IpAddr>().ok()
+            }
+            Source::SocketAddr => None,
+        };
+
+        if let Some(ip_addr) = ip_addr {
+            info!("Resolved real IP: {} (from {})", ip_addr, source);
+            return (ip_addr, *source);
+        }
+    }
 
+    info!("Using socket address: {}", addr.ip());
+    (addr.ip(), Source::SocketAddr)
+}
 
 fn header_as_str(value: &hyper::header::HeaderValue) -> Option<&str> {
     std::str::from_utf8(value.as_bytes()).ok()
 }
 
-fn pick_best_ip_from_options(
-    x_forwarded_for: Option<&str>,
-    real_ip: Option<&str>,
-    addr: SocketAddr,
-) -> (IpAddr, Source) {
-    let realip = x_forwarded_for.as_ref().and_then(|val| {
-        info!("Processing X-Forwarded-For header: {}", val);
-        let last_addr = get_last_addr_from_x_forwarded_for_header(val)?;
-        info!("Last address from X-Forwarded-For: {}", last_addr);
-
-        // 尝试解析 IP 地址，处理可能的端口号
-        parse_ip_address(last_addr).map(|ip_addr| (ip_addr, Source::XForwardedForHeader))
-    })
-    .or_else(|| {
-        real_ip.as_ref().and_then(|val| {
-            let addr = val.trim();
-            info!("Processing X-Real-Ip header: {}", val);
-            addr.parse::<IpAddr>().ok()
-                .map(|ip_addr| (ip_addr, Source::XRealIpHeader))
-        })
-    })
-    .unwrap_or_else(|| {
-        info!("Using socket address: {}", addr.ip());
-        (addr.ip(), Source::SocketAddr)
-    });
-
-    info!("Resolved real IP: {:?}", realip.0);
-    realip
+/// Selects the trustworthy address from a comma separated `X-Forwarded-For`
+/// chain, given how many trusted reverse proxy hops sit between this server
+/// and the client.
+///
+/// Each proxy in the chain appends the address it received the request
+/// from, so the rightmost entries are the ones added by proxies we trust,
+/// and the first untrusted entry to their left is the one we trust as the
+/// client's address. Returns `None` (falling through to the next source) if
+/// the chain is too short to contain such an entry, since an attacker can
+/// freely prepend arbitrary addresses to the left of the chain.
+fn get_trusted_addr_from_x_forwarded_for_header(value: &str, trusted_hops: usize) -> Option<&str> {
+    let addrs: Vec<&str> = value.split(',').map(|val| val.trim()).collect();
+    let index = addrs.len().checked_sub(trusted_hops + 1)?;
+    addrs.get(index).copied()
 }
 
-fn get_last_addr_from_x_forwarded_for_header(value: &str) -> Option<&str> {
-    value.split(',').map(|val| val.trim()).last()
+/// Returns the `for=` node of the first (client-most) forwarded-element in an
+/// RFC 7239 `Forwarded` header, e.g. `for=192.0.2.60;proto=http;by=203.0.113.43`
+/// -> `Some("192.0.2.60")`. Only that first element is examined: if it has no
+/// `for=` pair, `None` is returned and later elements are not searched.
+/// Values may be quoted, and may be obfuscated
+/// identifiers (`unknown`, `_gazonk`) rather than addresses at all; this
+/// function returns whatever it finds as-is and leaves recognising that it
+/// isn't a real address to `parse_ip_address`.
+fn get_first_addr_from_forwarded_header(value: &str) -> Option<&str> {
+    // Proxies each append their own forwarded-element to the end of this
+    // comma separated list, so the first element is the one added by the
+    // proxy closest to the original client.
+    let first_element = value.split(',').next()?.trim();
+
+    // Each element is a semicolon separated list of `key=value` pairs; find
+    // the one whose key is "for" (case-insensitively).
+    let for_pair = first_element
+        .split(';')
+        .map(|pair| pair.trim())
+        .find(|pair| match pair.split_once('=') {
+            Some((key, _)) => key.eq_ignore_ascii_case("for"),
+            None => false,
+        })?;
+
+    let (_, val) = for_pair.split_once('=')?;
+    Some(val.trim().trim_matches('"'))
 }
 
 fn parse_ip_address(value: &str) -> Option<IpAddr> {
-    // 如果 IP 地址包含端口号（尤其是 IPv6 地址），尝试只解析 IP 部分
-    let addr = if let Some(index) = value.rfind("]:") {
-        // 对于 IPv6 地址
-        &value[..=index]
+    // Strip a port suffix, if there is one, before parsing the remainder as
+    // an IP address.
+    let addr = if value.starts_with('[') && value.rfind("]:").is_some() {
+        // Bracketed IPv6 address with a port, e.g. "[::1]:8080". Guarded on
+        // `starts_with('[')` so the "]:" match can't be at byte offset 0 (as
+        // it would be for a malformed value like "]:"), which would panic
+        // when slicing from index 1.
+        let index = value.rfind("]:").unwrap();
+        &value[1..index]
+    } else if value.starts_with('[') && value.ends_with(']') {
+        // Bracketed IPv6 address without a port, e.g. "[::1]".
+        &value[1..value.len() - 1]
     } else if let Some(index) = value.rfind(':') {
-        // 对于 IPv4 地址
+        // IPv4 address with a port, e.g. "127.0.0.1:8080".
         &value[..index]
     } else {
-        // 不含端口号
+        // No port to strip.
         value
     };
 
@@ -145,13 +230,202 @@ mod test {
             (r#"for=192.0.2.43, for=198.51.100.17"#, "192.0.2.43"),
         ];
 
-        // for (value, expected) in examples {
-        //     assert_eq!(
-        //         get_first_addr_from_forwarded_header(value),
-        //         Some(expected),
-        //         "Header value: {}",
-        //         value
-        //     );
-        // }
+        for (value, expected) in examples {
+            assert_eq!(
+                get_first_addr_from_forwarded_header(value),
+                Some(expected),
+                "Header value: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn get_addr_from_forwarded_obfuscated_is_returned_as_is() {
+        // Obfuscated identifiers aren't addresses; it's parse_ip_address's job to
+        // reject them, not get_first_addr_from_forwarded_header's.
+        assert_eq!(
+            get_first_addr_from_forwarded_header(r#"for=unknown"#),
+            Some("unknown")
+        );
+        assert_eq!(parse_ip_address("unknown"), None);
+        assert_eq!(parse_ip_address("_gazonk"), None);
+    }
+
+    #[test]
+    fn get_addr_from_forwarded_ipv6() {
+        let value = r#"for="[2001:db8:cafe::17]:4711""#;
+        let addr = get_first_addr_from_forwarded_header(value).expect("for= node present");
+        assert_eq!(
+            parse_ip_address(addr),
+            Some("2001:db8:cafe::17".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_ip_address_strips_brackets_and_ports() {
+        assert_eq!(
+            parse_ip_address("[2001:db8:cafe::17]:4711"),
+            Some("2001:db8:cafe::17".parse().unwrap())
+        );
+        assert_eq!(
+            parse_ip_address("[2001:db8:cafe::17]"),
+            Some("2001:db8:cafe::17".parse().unwrap())
+        );
+        assert_eq!(
+            parse_ip_address("192.0.2.60:4711"),
+            Some("192.0.2.60".parse().unwrap())
+        );
+        assert_eq!(
+            parse_ip_address("192.0.2.60"),
+            Some("192.0.2.60".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_ip_address_does_not_panic_on_malformed_bracket_prefix() {
+        // Without the `starts_with('[')` guard, "]:" at byte offset 0 would
+        // panic when slicing `&value[1..index]`.
+        assert_eq!(parse_ip_address("]:"), None);
+        assert_eq!(parse_ip_address("]:8080"), None);
+    }
+
+    fn header_map(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn real_ip_prefers_forwarded_header_by_default() {
+        let headers = header_map(&[
+            ("forwarded", "for=192.0.2.60;proto=http"),
+            ("x-original-forwarded-for", "198.51.100.17"),
+            ("x-real-ip", "203.0.113.43"),
+        ]);
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let (ip, source) = real_ip(addr, &headers, &RealIpConfig::default());
+        assert_eq!(ip, "192.0.2.60".parse::<IpAddr>().unwrap());
+        assert!(matches!(source, Source::ForwardedHeader));
+    }
+
+    #[test]
+    fn real_ip_ignores_cf_connecting_ip_unless_configured() {
+        let headers = header_map(&[("cf-connecting-ip", "192.0.2.60")]);
+        let addr: SocketAddr = "203.0.113.43:0".parse().unwrap();
+
+        let (ip, source) = real_ip(addr, &headers, &RealIpConfig::default());
+        assert_eq!(ip, addr.ip());
+        assert!(matches!(source, Source::SocketAddr));
+
+        let config = RealIpConfig {
+            sources: vec![(Source::CfConnectingIpHeader, "cf-connecting-ip".to_owned())],
+            trusted_hops: 0,
+        };
+        let (ip, source) = real_ip(addr, &headers, &config);
+        assert_eq!(ip, "192.0.2.60".parse::<IpAddr>().unwrap());
+        assert!(matches!(source, Source::CfConnectingIpHeader));
+    }
+
+    #[test]
+    fn real_ip_handles_bare_ipv6_cf_connecting_ip() {
+        // Cloudflare sends IPv6 client addresses unbracketed and without a
+        // port, e.g. "2001:db8::1".
+        let headers = header_map(&[("cf-connecting-ip", "2001:db8::1")]);
+        let addr: SocketAddr = "203.0.113.43:0".parse().unwrap();
+        let config = RealIpConfig {
+            sources: vec![(Source::CfConnectingIpHeader, "cf-connecting-ip".to_owned())],
+            trusted_hops: 0,
+        };
+
+        let (ip, source) = real_ip(addr, &headers, &config);
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert!(matches!(source, Source::CfConnectingIpHeader));
+    }
+
+    #[test]
+    fn real_ip_respects_configured_source_order() {
+        let headers = header_map(&[
+            ("cf-connecting-ip", "192.0.2.60"),
+            ("x-original-forwarded-for", "198.51.100.17"),
+        ]);
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let config = RealIpConfig {
+            sources: vec![
+                (Source::CfConnectingIpHeader, "cf-connecting-ip".to_owned()),
+                (Source::XForwardedForHeader, "x-original-forwarded-for".to_owned()),
+            ],
+            trusted_hops: 0,
+        };
+
+        let (ip, source) = real_ip(addr, &headers, &config);
+        assert_eq!(ip, "192.0.2.60".parse::<IpAddr>().unwrap());
+        assert!(matches!(source, Source::CfConnectingIpHeader));
+    }
+
+    #[test]
+    fn real_ip_falls_back_to_socket_addr_when_no_sources_configured() {
+        let headers = header_map(&[("x-original-forwarded-for", "198.51.100.17")]);
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let config = RealIpConfig { sources: vec![], trusted_hops: 0 };
+
+        let (ip, source) = real_ip(addr, &headers, &config);
+        assert_eq!(ip, addr.ip());
+        assert!(matches!(source, Source::SocketAddr));
+    }
+
+    #[test]
+    fn trusted_addr_from_x_forwarded_for_zero_hops_matches_old_behaviour() {
+        // With no trusted hops declared, we trust the rightmost (last) entry,
+        // same as the server always used to.
+        assert_eq!(
+            get_trusted_addr_from_x_forwarded_for_header("203.0.113.1, 198.51.100.2", 0),
+            Some("198.51.100.2")
+        );
+    }
+
+    #[test]
+    fn trusted_addr_from_x_forwarded_for_with_hops() {
+        let chain = "203.0.113.1, 198.51.100.2, 192.0.2.3";
+
+        // One trusted hop: skip the rightmost entry (our own proxy), trust the
+        // one before it.
+        assert_eq!(
+            get_trusted_addr_from_x_forwarded_for_header(chain, 1),
+            Some("198.51.100.2")
+        );
+        // Two trusted hops: skip the rightmost two.
+        assert_eq!(
+            get_trusted_addr_from_x_forwarded_for_header(chain, 2),
+            Some("203.0.113.1")
+        );
+    }
+
+    #[test]
+    fn trusted_addr_from_x_forwarded_for_rejects_spoofed_prefix() {
+        // An attacker prepending fake addresses to the chain can't push their
+        // way past the trusted hop count.
+        let genuine = "203.0.113.1, 198.51.100.2";
+        let spoofed = "6.6.6.6, 203.0.113.1, 198.51.100.2";
+
+        assert_eq!(
+            get_trusted_addr_from_x_forwarded_for_header(genuine, 1),
+            get_trusted_addr_from_x_forwarded_for_header(spoofed, 1),
+        );
+    }
+
+    #[test]
+    fn trusted_addr_from_x_forwarded_for_chain_too_short_falls_back() {
+        assert_eq!(
+            get_trusted_addr_from_x_forwarded_for_header("203.0.113.1", 1),
+            None
+        );
+        assert_eq!(
+            get_trusted_addr_from_x_forwarded_for_header("203.0.113.1", 5),
+            None
+        );
     }
 }